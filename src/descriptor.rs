@@ -92,12 +92,22 @@ pub struct DeviceTree {
 pub struct ConfigurationTree {
     pub desc: ConfigurationDescriptor,
     pub interfaces: Vec<InterfaceTree>,
+    /// Class-specific or otherwise unrecognized descriptors found between
+    /// the configuration descriptor and its first interface, e.g. an
+    /// Interface Association Descriptor. Each entry is the raw descriptor
+    /// bytes, `bLength` included.
+    pub extra: Vec<Vec<u8>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct InterfaceTree {
     pub desc: InterfaceDescriptor,
     pub endpoints: Vec<EndpointDescriptor>,
+    /// Class-specific or otherwise unrecognized descriptors found under
+    /// this interface (alternate setting), e.g. a HID or CDC functional
+    /// descriptor. Each entry is the raw descriptor bytes, `bLength`
+    /// included.
+    pub extra: Vec<Vec<u8>>,
 }
 
 impl DeviceTree {
@@ -135,12 +145,19 @@ impl ConfigurationTree {
         desc: ConfigurationDescriptor,
         descriptors: &[AnyDescriptor],
     ) -> Result<Self> {
+        let interface_groups = split_by_parent_desc::<InterfaceDescriptor>(descriptors);
+        let first_interface = descriptors
+            .iter()
+            .position(|d| <&InterfaceDescriptor>::try_from(d).is_ok())
+            .unwrap_or(descriptors.len());
         Ok(Self {
             desc,
-            interfaces: split_by_parent_desc::<InterfaceDescriptor>(descriptors)
+            interfaces: interface_groups
                 .iter()
                 .map(|(d, ds)| InterfaceTree::from_descriptors((*d).clone(), ds))
                 .collect::<Result<Vec<_>>>()?,
+            // Skip descriptors[0], which is the configuration descriptor itself.
+            extra: other_descriptors(&descriptors[1..first_interface]),
         })
     }
 }
@@ -155,10 +172,22 @@ impl InterfaceTree {
                     Some(d?.clone())
                 })
                 .collect(),
+            // Skip descriptors[0], which is the interface descriptor itself.
+            extra: other_descriptors(&descriptors[1..]),
         })
     }
 }
 
+fn other_descriptors(descriptors: &[AnyDescriptor]) -> Vec<Vec<u8>> {
+    descriptors
+        .iter()
+        .filter_map(|d| match d {
+            AnyDescriptor::Other(bytes) => Some(bytes.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
@@ -188,7 +217,9 @@ macro_rules! any_descriptor {
             $(
                 $name($name),
             )*
-            Other(u8),
+            /// Raw bytes of a descriptor type we don't otherwise model,
+            /// `bLength` and `bDescriptorType` included.
+            Other(Vec<u8>),
         }
         $(
             impl<'a> TryFrom<&'a AnyDescriptor> for &'a $name {
@@ -256,7 +287,7 @@ fn byte_array_to_descriptors(mut data: &[u8]) -> Result<Vec<AnyDescriptor>> {
             2 => AnyDescriptor::ConfigurationDescriptor(parse_descriptor(descriptor_data)?),
             4 => AnyDescriptor::InterfaceDescriptor(parse_descriptor(descriptor_data)?),
             5 => AnyDescriptor::EndpointDescriptor(parse_descriptor(descriptor_data)?),
-            o => AnyDescriptor::Other(o),
+            _ => AnyDescriptor::Other(descriptor_data.to_vec()),
         });
         data = &data[l..]
     }
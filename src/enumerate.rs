@@ -0,0 +1,196 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of tiny-linux-usb
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+use std::{
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{Error, Result, UsbDevice};
+
+/// A device found by [`enumerate`] or [`DeviceFilter::find`]. Opening it is
+/// deferred to [`Self::open`], so a filter can be applied over many devices
+/// without touching `/dev/bus/usb` for each of them.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub bcd_device: u16,
+    pub bus: u16,
+    pub address: u16,
+    /// `bDeviceClass` of the device itself; `0` for composite devices that
+    /// declare their class per-interface instead.
+    pub device_class: u8,
+    /// `bInterfaceClass` of every interface found on the device, across all
+    /// configurations currently reported by sysfs.
+    pub interface_classes: Vec<u8>,
+}
+
+impl DeviceInfo {
+    pub fn open(&self) -> Result<UsbDevice> {
+        let usb_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/dev/bus/usb/{:03}/{:03}", self.bus, self.address))?;
+        UsbDevice::new(usb_file)
+    }
+}
+
+/// Builder over [`enumerate`], for finding devices by VID/PID, device or
+/// interface class, or serial string without reimplementing the sysfs scan.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    vid_pid: Option<(u16, u16)>,
+    device_class: Option<u8>,
+    interface_class: Option<u8>,
+    serial: Option<String>,
+}
+
+impl DeviceFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn vid_pid(mut self, vid: u16, pid: u16) -> Self {
+        self.vid_pid = Some((vid, pid));
+        self
+    }
+
+    pub fn device_class(mut self, device_class: u8) -> Self {
+        self.device_class = Some(device_class);
+        self
+    }
+
+    pub fn interface_class(mut self, interface_class: u8) -> Self {
+        self.interface_class = Some(interface_class);
+        self
+    }
+
+    pub fn serial(mut self, serial: impl Into<String>) -> Self {
+        self.serial = Some(serial.into());
+        self
+    }
+
+    /// Runs [`enumerate`] and keeps only the devices matching every
+    /// criterion set on this filter. A serial-string filter opens each
+    /// remaining candidate to read its string descriptor, since that isn't
+    /// available from sysfs.
+    pub fn find(&self) -> Result<Vec<DeviceInfo>> {
+        enumerate()?
+            .into_iter()
+            .filter_map(|info| match self.matches(&info) {
+                Ok(true) => Some(Ok(info)),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+    }
+
+    fn matches(&self, info: &DeviceInfo) -> Result<bool> {
+        if let Some((vid, pid)) = self.vid_pid {
+            if info.vid != vid || info.pid != pid {
+                return Ok(false);
+            }
+        }
+        if let Some(device_class) = self.device_class {
+            if info.device_class != device_class {
+                return Ok(false);
+            }
+        }
+        if let Some(interface_class) = self.interface_class {
+            if !info.interface_classes.contains(&interface_class) {
+                return Ok(false);
+            }
+        }
+        if let Some(serial) = &self.serial {
+            let device = info.open()?;
+            let found_serial = device.serial_number(Duration::from_millis(100));
+            device.close()?;
+            if found_serial?.as_deref() != Some(serial.as_str()) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Walks `/sys/bus/usb/devices/` and returns every USB device found there.
+/// Unlike the sysfs parsing [`crate::open_device_vid_pid_endpoint`] used to
+/// do, malformed attribute files are reported as an error instead of
+/// panicking.
+pub fn enumerate() -> Result<Vec<DeviceInfo>> {
+    let entry_paths: Vec<PathBuf> = std::fs::read_dir("/sys/bus/usb/devices/")?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<_>>()?;
+
+    let mut devices = Vec::new();
+    for path in &entry_paths {
+        // Interface directories (e.g. "1-1:1.0") have no idVendor; skip them,
+        // they are only consulted below for their bInterfaceClass.
+        let Some(vid) = read_sysfs_hex_u16(path, "idVendor")? else {
+            continue;
+        };
+        let pid = read_sysfs_hex_u16(path, "idProduct")?.ok_or(Error::InvalidSysfsEntry)?;
+        let bcd_device = read_sysfs_hex_u16(path, "bcdDevice")?.unwrap_or(0);
+        let bus = read_sysfs_decimal(path, "busnum")?.ok_or(Error::InvalidSysfsEntry)?;
+        let address = read_sysfs_decimal(path, "devnum")?.ok_or(Error::InvalidSysfsEntry)?;
+        let device_class = read_sysfs_hex_u8(path, "bDeviceClass")?.unwrap_or(0);
+
+        let device_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or(Error::InvalidSysfsEntry)?;
+        let interface_prefix = format!("{device_name}:");
+        let mut interface_classes = Vec::new();
+        for interface_path in &entry_paths {
+            let Some(name) = interface_path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !name.starts_with(&interface_prefix) {
+                continue;
+            }
+            if let Some(class) = read_sysfs_hex_u8(interface_path, "bInterfaceClass")? {
+                interface_classes.push(class);
+            }
+        }
+
+        devices.push(DeviceInfo {
+            vid,
+            pid,
+            bcd_device,
+            bus,
+            address,
+            device_class,
+            interface_classes,
+        });
+    }
+    Ok(devices)
+}
+
+fn read_sysfs_attribute(path: &Path, attribute: &str) -> Result<Option<String>> {
+    match std::fs::read_to_string(path.join(attribute)) {
+        Ok(s) => Ok(Some(s.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_sysfs_hex_u16(path: &Path, attribute: &str) -> Result<Option<u16>> {
+    read_sysfs_attribute(path, attribute)?
+        .map(|s| u16::from_str_radix(&s, 16).map_err(|_| Error::InvalidSysfsEntry))
+        .transpose()
+}
+
+fn read_sysfs_hex_u8(path: &Path, attribute: &str) -> Result<Option<u8>> {
+    read_sysfs_attribute(path, attribute)?
+        .map(|s| u8::from_str_radix(&s, 16).map_err(|_| Error::InvalidSysfsEntry))
+        .transpose()
+}
+
+fn read_sysfs_decimal(path: &Path, attribute: &str) -> Result<Option<u16>> {
+    read_sysfs_attribute(path, attribute)?
+        .map(|s| s.parse().map_err(|_| Error::InvalidSysfsEntry))
+        .transpose()
+}
@@ -4,7 +4,7 @@
 
 use std::ffi::{c_int, c_uint, c_void};
 
-use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr, request_code_none};
+use nix::{ioctl_none, ioctl_read, ioctl_readwrite, ioctl_write_ptr, request_code_none};
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -18,13 +18,13 @@ pub struct ControlTransfer {
     pub data: *mut c_void,
 }
 
+/// Mirrors `struct usbdevfs_setinterface`, the argument to
+/// `USBDEVFS_SETINTERFACE`.
 #[repr(C)]
 #[derive(Debug, Clone)]
-pub struct BulkTransfer {
-    pub ep: c_uint,
-    pub len: c_uint,
-    pub timeout: c_uint,
-    pub data: *mut c_void,
+pub struct SetInterface {
+    pub interface: c_uint,
+    pub altsetting: c_uint,
 }
 
 #[repr(C)]
@@ -42,10 +42,55 @@ pub struct SubIoctl {
     pub data: *mut c_void,
 }
 
+/// A single isochronous packet descriptor, as it trails a [`Urb`] with
+/// `number_of_packets` > 0. Unused for the control/bulk/interrupt transfers
+/// this crate currently submits, but it has to be present for the struct
+/// layout (and size accounting done by the kernel) to line up.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct IsoPacketDescriptor {
+    pub length: c_uint,
+    pub actual_length: c_uint,
+    pub status: c_uint,
+}
+
+#[repr(C)]
+pub union UrbPacketsOrStreamId {
+    pub number_of_packets: c_int,
+    pub stream_id: c_int,
+}
+
+/// Mirrors `struct usbdevfs_urb`. `iso_frame_desc` is the kernel's flexible
+/// array member; we never allocate one, since this crate only submits
+/// control/bulk/interrupt URBs (`number_of_packets` is always 0).
+#[repr(C)]
+pub struct Urb {
+    pub r#type: u8,
+    pub endpoint: u8,
+    pub status: c_int,
+    pub flags: c_uint,
+    pub buffer: *mut c_void,
+    pub buffer_length: c_int,
+    pub actual_length: c_int,
+    pub start_frame: c_int,
+    pub packets_or_stream_id: UrbPacketsOrStreamId,
+    pub error_count: c_int,
+    pub signr: c_uint,
+    pub usercontext: *mut c_void,
+    pub iso_frame_desc: [IsoPacketDescriptor; 0],
+}
+
 pub const IOCTL_USBFS_DISCONNECT: c_int = request_code_none!('U', 22) as i32;
 ioctl_readwrite!(usbdevfs_control, 'U', 0, ControlTransfer);
-// This can do interrupts. See the kernel docs for usb_bulk_msg
-ioctl_readwrite!(usbdevfs_bulk, 'U', 2, BulkTransfer);
+ioctl_read!(usbdevfs_resetep, 'U', 3, c_uint);
+ioctl_read!(usbdevfs_setinterface, 'U', 4, SetInterface);
+ioctl_read!(usbdevfs_setconfiguration, 'U', 5, c_uint);
 ioctl_write_ptr!(usbdevfs_get_driver, 'U', 8, GetDriver);
+ioctl_readwrite!(usbdevfs_submiturb, 'U', 10, Urb);
+ioctl_write_ptr!(usbdevfs_discardurb, 'U', 11, Urb);
+ioctl_read!(usbdevfs_reapurb, 'U', 12, *mut c_void);
+ioctl_read!(usbdevfs_reapurbndelay, 'U', 13, *mut c_void);
 ioctl_read!(usbdevfs_claim_interface, 'U', 15, c_uint);
 ioctl_readwrite!(usbdevfs_ioctl, 'U', 18, SubIoctl);
+ioctl_none!(usbdevfs_reset, 'U', 20);
+ioctl_read!(usbdevfs_clear_halt, 'U', 21, c_uint);
@@ -3,15 +3,21 @@
 // Licensed under the MIT license. See LICENSE file in the project root for details.
 
 mod descriptor;
+mod enumerate;
 mod ioctl;
+#[cfg(feature = "usbtmc")]
+pub mod usbtmc;
+
+pub use enumerate::{enumerate, DeviceFilter, DeviceInfo};
 
 use std::{
-    cell::OnceCell,
+    cell::{OnceCell, Ref, RefCell},
+    collections::{hash_map::Entry, HashMap},
     ffi::c_void,
-    fs::{File, OpenOptions},
+    fs::File,
     io::{Read, Seek},
     os::fd::{FromRawFd, IntoRawFd},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use ioctl::{usbdevfs_control, ControlTransfer};
@@ -19,15 +25,21 @@ use ioctl::{usbdevfs_control, ControlTransfer};
 use crate::{
     descriptor::DeviceTree,
     ioctl::{
-        usbdevfs_bulk, usbdevfs_claim_interface, usbdevfs_ioctl, BulkTransfer, SubIoctl,
-        IOCTL_USBFS_DISCONNECT,
+        usbdevfs_claim_interface, usbdevfs_clear_halt, usbdevfs_discardurb, usbdevfs_ioctl,
+        usbdevfs_reapurb, usbdevfs_reapurbndelay, usbdevfs_reset, usbdevfs_resetep,
+        usbdevfs_setconfiguration, usbdevfs_setinterface, usbdevfs_submiturb, SetInterface,
+        SubIoctl, Urb, UrbPacketsOrStreamId, IOCTL_USBFS_DISCONNECT,
     },
 };
 
 #[derive(Debug, Clone)]
 pub struct UsbDevice {
     fd: i32,
-    descriptor_cache: OnceCell<DeviceTree>,
+    descriptor_cache: RefCell<OnceCell<DeviceTree>>,
+    /// Endpoints with a [`TransferHandle`] submitted but not yet reaped, so
+    /// `clear_halt`/`reset_endpoint`/`reset_device` can refuse to run while
+    /// the outcome would be undefined.
+    in_flight_endpoints: RefCell<HashMap<u8, u32>>,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -40,6 +52,13 @@ pub enum Error {
     InvalidEndpoint,
     DeviceDisconnected,
     NotFound,
+    TransferCancelled,
+    EndpointBusy,
+    InvalidStringDescriptor,
+    TimedOut,
+    /// A sysfs attribute file was missing or didn't contain the expected
+    /// format, while enumerating devices.
+    InvalidSysfsEntry,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -56,6 +75,51 @@ pub enum RequestType {
     Reserved = 3 << 5,
 }
 
+/// The kind of endpoint a [`Urb`] is submitted against.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UrbType {
+    Iso = 0,
+    Interrupt = 1,
+    Control = 2,
+    Bulk = 3,
+}
+
+/// A completed transfer, returned by [`UsbDevice::reap`].
+#[derive(Debug)]
+pub struct Transfer {
+    pub endpoint: u8,
+    pub buffer: Vec<u8>,
+    pub status: i32,
+}
+
+/// A pinned, in-flight URB. Dropping this without reaping it leaks the
+/// underlying buffer until the kernel completes (and [`UsbDevice::reap`]
+/// frees) the transfer, so always reap or at least cancel and then reap.
+#[derive(Debug)]
+pub struct TransferHandle<'a> {
+    device: &'a UsbDevice,
+    urb: *mut UrbSlot,
+}
+
+impl<'a> TransferHandle<'a> {
+    /// Requests cancellation of the transfer via `USBDEVFS_DISCARDURB`. The
+    /// transfer still has to be reaped afterwards; it comes back with
+    /// `Error::TransferCancelled`, unless it had already completed, in which
+    /// case it is reaped with its data like any other transfer.
+    pub fn cancel(&self) -> Result<()> {
+        unsafe { usbdevfs_discardurb(self.device.fd, self.urb as *mut Urb)? };
+        Ok(())
+    }
+}
+
+/// The box backing a submitted [`Urb`]: the buffer has to live next to the
+/// `Urb` itself so the whole thing can be recovered from the raw pointer
+/// the kernel hands back on reap.
+struct UrbSlot {
+    urb: Urb,
+    buffer: Vec<u8>,
+}
+
 /// Recipients of control transfers.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Recipient {
@@ -70,10 +134,21 @@ impl UsbDevice {
         let fd = fd.into_raw_fd();
         Ok(Self {
             fd,
-            descriptor_cache: OnceCell::new(),
+            descriptor_cache: RefCell::new(OnceCell::new()),
+            in_flight_endpoints: RefCell::new(HashMap::new()),
         })
     }
 
+    /// Closes the underlying file descriptor. `UsbDevice` otherwise keeps it
+    /// open forever (see [`Self::descriptors`]), which is fine for a single
+    /// long-lived handle but leaks one fd per call for discovery paths like
+    /// [`DeviceFilter`] that open many candidate devices just to inspect
+    /// them.
+    pub(crate) fn close(self) -> Result<()> {
+        nix::unistd::close(self.fd)?;
+        Ok(())
+    }
+
     pub fn claim_interface(&self, interface: u8) -> Result<()> {
         let mut command = SubIoctl {
             ifno: interface as i32,
@@ -89,70 +164,257 @@ impl UsbDevice {
         Ok(())
     }
 
+    /// Finds `endpoint_address` across every configuration and alternate
+    /// setting in [`Self::descriptors`], claims the owning interface, and
+    /// switches to that alternate setting via [`Self::set_interface`] if it
+    /// isn't the default (0).
     pub fn claim_endpoint(&self, endpoint_address: u8) -> Result<()> {
-        let descriptors = self.descriptors()?;
-        let mut interface_to_claim = None;
-        'outer: for interface in &descriptors
-            .configurations
-            .get(0)
-            .ok_or(Error::InvalidEndpoint)?
-            .interfaces
+        let found = {
+            let descriptors = self.descriptors()?;
+            descriptors
+                .configurations
+                .iter()
+                .flat_map(|c| &c.interfaces)
+                .find_map(|interface| {
+                    interface
+                        .endpoints
+                        .iter()
+                        .any(|e| e.bEndpointAddress == endpoint_address)
+                        .then_some((
+                            interface.desc.bInterfaceNumber,
+                            interface.desc.bAlternateSetting,
+                        ))
+                })
+        };
+        let (interface_number, alt_setting) = found.ok_or(Error::InvalidEndpoint)?;
+        self.claim_interface(interface_number)?;
+        if alt_setting != 0 {
+            self.set_interface(interface_number, alt_setting)?;
+        }
+        Ok(())
+    }
+
+    /// Selects the active configuration via `USBDEVFS_SETCONFIGURATION`.
+    /// Same caveat as [`Self::reset_device`] about queued URBs, and the
+    /// cached descriptors are invalidated just like on reset.
+    pub fn set_configuration(&self, config_value: u8) -> Result<()> {
+        if !self.in_flight_endpoints.borrow().is_empty() {
+            return Err(Error::EndpointBusy);
+        }
+        let mut value = config_value as u32;
+        unsafe { usbdevfs_setconfiguration(self.fd, &mut value as *mut _)? };
+        *self.descriptor_cache.borrow_mut() = OnceCell::new();
+        Ok(())
+    }
+
+    /// Selects an alternate setting for `interface` via
+    /// `USBDEVFS_SETINTERFACE`.
+    pub fn set_interface(&self, interface: u8, alt_setting: u8) -> Result<()> {
+        let mut request = SetInterface {
+            interface: interface as u32,
+            altsetting: alt_setting as u32,
+        };
+        unsafe { usbdevfs_setinterface(self.fd, &mut request as *mut _)? };
+        Ok(())
+    }
+
+    /// Clears a stalled endpoint via `USBDEVFS_CLEAR_HALT`. Per the kernel
+    /// docs, clearing a halt while URBs are still queued against the
+    /// endpoint is undefined, so this refuses while any submitted
+    /// [`TransferHandle`] for it hasn't been reaped yet.
+    pub fn clear_halt(&self, endpoint: u8) -> Result<()> {
+        self.ensure_endpoint_idle(endpoint)?;
+        let mut ep = endpoint as u32;
+        unsafe { usbdevfs_clear_halt(self.fd, &mut ep as *mut _)? };
+        Ok(())
+    }
+
+    /// Resets a single endpoint via `USBDEVFS_RESETEP`, clearing its data
+    /// toggle and stall state. Same caveat as [`Self::clear_halt`] about
+    /// queued URBs.
+    pub fn reset_endpoint(&self, endpoint: u8) -> Result<()> {
+        self.ensure_endpoint_idle(endpoint)?;
+        let mut ep = endpoint as u32;
+        unsafe { usbdevfs_resetep(self.fd, &mut ep as *mut _)? };
+        Ok(())
+    }
+
+    /// Resets the whole device via `USBDEVFS_RESET`. The kernel
+    /// re-enumerates the device as part of this, so the cached descriptors
+    /// no longer apply; refuses while any endpoint still has a submitted
+    /// [`TransferHandle`] that hasn't been reaped, for the same reason as
+    /// [`Self::clear_halt`].
+    pub fn reset_device(&self) -> Result<()> {
+        if !self.in_flight_endpoints.borrow().is_empty() {
+            return Err(Error::EndpointBusy);
+        }
+        unsafe { usbdevfs_reset(self.fd)? };
+        *self.descriptor_cache.borrow_mut() = OnceCell::new();
+        Ok(())
+    }
+
+    fn ensure_endpoint_idle(&self, endpoint: u8) -> Result<()> {
+        if self.in_flight_endpoints.borrow().contains_key(&endpoint) {
+            return Err(Error::EndpointBusy);
+        }
+        Ok(())
+    }
+
+    pub fn descriptors(&self) -> Result<Ref<'_, DeviceTree>> {
+        if self.descriptor_cache.borrow().get().is_none() {
+            let mut fd_as_file = unsafe { File::from_raw_fd(self.fd) };
+            let mut descriptor_data = Vec::new();
+            fd_as_file.rewind()?;
+            fd_as_file.read_to_end(&mut descriptor_data)?;
+            // Don't close the fd
+            std::mem::forget(fd_as_file);
+            self.descriptor_cache
+                .borrow()
+                .set(DeviceTree::from_byte_array(&descriptor_data)?)
+                .unwrap();
+        }
+        Ok(Ref::map(self.descriptor_cache.borrow(), |c| {
+            c.get().unwrap()
+        }))
+    }
+
+    /// Submits a URB for asynchronous completion. The `buffer` is moved onto
+    /// the heap alongside the URB itself, so it stays valid for as long as
+    /// the kernel needs it; it comes back (truncated to `actual_length`) in
+    /// the [`Transfer`] returned by [`Self::reap`].
+    pub fn submit(
+        &self,
+        endpoint: u8,
+        urb_type: UrbType,
+        buffer: Vec<u8>,
+    ) -> Result<TransferHandle<'_>> {
+        let mut slot = Box::new(UrbSlot {
+            urb: Urb {
+                r#type: urb_type as u8,
+                endpoint,
+                status: 0,
+                flags: 0,
+                buffer: std::ptr::null_mut(),
+                buffer_length: buffer.len() as i32,
+                actual_length: 0,
+                start_frame: 0,
+                packets_or_stream_id: UrbPacketsOrStreamId {
+                    number_of_packets: 0,
+                },
+                error_count: 0,
+                signr: 0,
+                usercontext: std::ptr::null_mut(),
+                iso_frame_desc: [],
+            },
+            buffer,
+        });
+        slot.urb.buffer = slot.buffer.as_mut_ptr() as *mut c_void;
+        let slot = Box::into_raw(slot);
+        unsafe { (*slot).urb.usercontext = slot as *mut c_void };
+        if let Err(e) = unsafe { usbdevfs_submiturb(self.fd, &mut (*slot).urb as *mut Urb) } {
+            // SUBMITURB failed synchronously, so the kernel never took
+            // ownership of the slot; we have to free it ourselves.
+            drop(unsafe { Box::from_raw(slot) });
+            return Err(e.into());
+        }
+        *self
+            .in_flight_endpoints
+            .borrow_mut()
+            .entry(endpoint)
+            .or_insert(0) += 1;
+        Ok(TransferHandle {
+            device: self,
+            urb: slot,
+        })
+    }
+
+    /// Reaps a completed URB via `USBDEVFS_REAPURB` (`blocking`) or
+    /// `USBDEVFS_REAPURBNDELAY`, recovering the [`UrbSlot`] that was pinned
+    /// on [`Self::submit`] from the `usercontext` pointer the kernel hands
+    /// back. This reaps whichever URB completes first, not necessarily a
+    /// particular [`TransferHandle`], so don't interleave manual `submit`
+    /// calls with the blocking `read_bulk`/`write_bulk` wrappers on the same
+    /// device.
+    pub fn reap(&self, blocking: bool) -> Result<Transfer> {
+        let mut completed: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            if blocking {
+                usbdevfs_reapurb(self.fd, &mut completed as *mut _)?;
+            } else {
+                usbdevfs_reapurbndelay(self.fd, &mut completed as *mut _)?;
+            }
+        }
+        let slot = unsafe { Box::from_raw((*(completed as *mut Urb)).usercontext as *mut UrbSlot) };
+        if let Entry::Occupied(mut e) = self
+            .in_flight_endpoints
+            .borrow_mut()
+            .entry(slot.urb.endpoint)
         {
-            for endpoint in &interface.endpoints {
-                if endpoint.bEndpointAddress == endpoint_address {
-                    interface_to_claim = Some(interface.desc.bInterfaceNumber);
-                    break 'outer;
-                }
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
             }
         }
-        match interface_to_claim {
-            Some(i) => self.claim_interface(i),
-            None => Err(Error::InvalidEndpoint),
+        let status = slot.urb.status;
+        if status == -(nix::errno::Errno::ECONNRESET as i32) {
+            return Err(Error::TransferCancelled);
         }
+        let mut buffer = slot.buffer;
+        buffer.truncate(slot.urb.actual_length as usize);
+        Ok(Transfer {
+            endpoint: slot.urb.endpoint,
+            buffer,
+            status,
+        })
     }
 
-    pub fn descriptors(&self) -> Result<&DeviceTree> {
-        if let Some(d) = self.descriptor_cache.get() {
-            return Ok(d);
+    /// Submits a URB and waits for it to complete, polling
+    /// `REAPURBNDELAY` rather than blocking on `REAPURB` since `usbdevfs_urb`
+    /// has no timeout field of its own. Past the deadline, the transfer is
+    /// cancelled and reaped so the slot doesn't leak; a cancellation that
+    /// loses the race against real completion still returns the data.
+    fn submit_and_await(
+        &self,
+        endpoint: u8,
+        urb_type: UrbType,
+        buffer: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Transfer> {
+        let handle = self.submit(endpoint, urb_type, buffer)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.reap(false) {
+                Err(Error::IoctlError(nix::errno::Errno::EAGAIN)) => {
+                    if Instant::now() >= deadline {
+                        let _ = handle.cancel();
+                        return match self.reap(true) {
+                            Err(Error::TransferCancelled) => Err(Error::TimedOut),
+                            other => other,
+                        };
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                other => return other,
+            }
         }
-        let mut fd_as_file = unsafe { File::from_raw_fd(self.fd) };
-        let mut descriptor_data = Vec::new();
-        fd_as_file.rewind()?;
-        fd_as_file.read_to_end(&mut descriptor_data)?;
-        // Don't close the fd
-        std::mem::forget(fd_as_file);
-        self.descriptor_cache
-            .set(DeviceTree::from_byte_array(&descriptor_data)?)
-            .unwrap();
-        Ok(self.descriptor_cache.get().unwrap())
     }
 
     pub fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
         if endpoint & 0x80 == 0 {
             return Err(Error::InvalidEndpoint);
         }
-        let mut bulk_desc = BulkTransfer {
-            ep: endpoint as u32,
-            len: buf.len() as u32,
-            timeout: timeout.as_millis() as u32,
-            data: buf.as_mut_ptr() as *mut c_void,
-        };
-        unsafe { usbdevfs_bulk(self.fd, &mut bulk_desc as *mut _)? };
-        Ok(bulk_desc.len as usize)
+        let transfer =
+            self.submit_and_await(endpoint, UrbType::Bulk, vec![0u8; buf.len()], timeout)?;
+        buf[..transfer.buffer.len()].copy_from_slice(&transfer.buffer);
+        Ok(transfer.buffer.len())
     }
 
     pub fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> Result<usize> {
         if endpoint & 0x80 != 0 {
             return Err(Error::InvalidEndpoint);
         }
-        let mut bulk_desc = BulkTransfer {
-            ep: endpoint as u32,
-            len: buf.len() as u32,
-            timeout: timeout.as_millis() as u32,
-            data: buf.as_ptr() as *mut c_void,
-        };
-        unsafe { usbdevfs_bulk(self.fd, &mut bulk_desc as *mut _)? };
-        Ok(bulk_desc.len as usize)
+        let transfer = self.submit_and_await(endpoint, UrbType::Bulk, buf.to_vec(), timeout)?;
+        Ok(transfer.buffer.len())
     }
     pub fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
         self.read_bulk(endpoint, buf, timeout)
@@ -211,6 +473,96 @@ impl UsbDevice {
         unsafe { usbdevfs_control(self.fd, &mut desc as *mut _)? };
         Ok(desc.length as usize)
     }
+
+    /// Reads and decodes string descriptor `index` in the given `lang_id`,
+    /// via a standard `GET_DESCRIPTOR` control IN request. `index` 0 is the
+    /// language-ID list rather than a string; use [`Self::supported_languages`]
+    /// for that instead.
+    pub fn read_string_descriptor_raw(
+        &self,
+        index: u8,
+        lang_id: u16,
+        timeout: Duration,
+    ) -> Result<String> {
+        let bytes = self.get_string_descriptor_bytes(index, lang_id, timeout)?;
+        Ok(String::from_utf16_lossy(&utf16le_units(&bytes)))
+    }
+
+    /// Reads string descriptor 0, returning the LANGIDs the device supports.
+    pub fn supported_languages(&self, timeout: Duration) -> Result<Vec<u16>> {
+        let bytes = self.get_string_descriptor_bytes(0, 0, timeout)?;
+        Ok(utf16le_units(&bytes))
+    }
+
+    /// Resolves `iManufacturer` to text, in the first language the device
+    /// reports support for. Returns `None` if the device has no manufacturer
+    /// string.
+    pub fn manufacturer(&self, timeout: Duration) -> Result<Option<String>> {
+        let index = self.descriptors()?.desc.iManufacturer;
+        self.read_default_language_string(index, timeout)
+    }
+
+    /// Resolves `iProduct` to text, same rules as [`Self::manufacturer`].
+    pub fn product(&self, timeout: Duration) -> Result<Option<String>> {
+        let index = self.descriptors()?.desc.iProduct;
+        self.read_default_language_string(index, timeout)
+    }
+
+    /// Resolves `iSerialNumber` to text, same rules as [`Self::manufacturer`].
+    pub fn serial_number(&self, timeout: Duration) -> Result<Option<String>> {
+        let index = self.descriptors()?.desc.iSerialNumber;
+        self.read_default_language_string(index, timeout)
+    }
+
+    fn read_default_language_string(&self, index: u8, timeout: Duration) -> Result<Option<String>> {
+        if index == 0 {
+            return Ok(None);
+        }
+        let lang_id = *self
+            .supported_languages(timeout)?
+            .first()
+            .ok_or(Error::InvalidStringDescriptor)?;
+        self.read_string_descriptor_raw(index, lang_id, timeout)
+            .map(Some)
+    }
+
+    fn get_string_descriptor_bytes(
+        &self,
+        index: u8,
+        lang_id: u16,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let mut buf = [0u8; 255];
+        let len = self.read_control(
+            request_type(Direction::In, RequestType::Standard, Recipient::Device),
+            GET_DESCRIPTOR,
+            (STRING_DESCRIPTOR_TYPE as u16) << 8 | index as u16,
+            lang_id,
+            &mut buf,
+            timeout,
+        )?;
+        if len < 2 || buf[1] != STRING_DESCRIPTOR_TYPE {
+            return Err(Error::InvalidStringDescriptor);
+        }
+        // `read_control`/`USBDEVFS_CONTROL` report back the requested buffer
+        // length, not the device's actual transfer size, so bound the
+        // payload by the descriptor's own `bLength` instead of trusting
+        // `len` to exclude the zero-padding left in `buf`.
+        let end = (buf[0] as usize).min(len).max(2);
+        Ok(buf[2..end].to_vec())
+    }
+}
+
+const GET_DESCRIPTOR: u8 = 6;
+const STRING_DESCRIPTOR_TYPE: u8 = 3;
+
+/// Decodes a UTF-16LE byte string, as used by USB string descriptors,
+/// dropping a trailing odd byte rather than erroring on it.
+fn utf16le_units(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect()
 }
 
 pub fn request_type(direction: Direction, request_type: RequestType, recipient: Recipient) -> u8 {
@@ -259,33 +611,26 @@ impl std::fmt::Display for Error {
             Error::DeviceDisconnected => f.write_str("Device disconnected"),
             Error::InvalidEndpoint => f.write_str("Invalid endpoint"),
             Error::NotFound => f.write_str("Not found"),
+            Error::TransferCancelled => f.write_str("Transfer was cancelled"),
+            Error::EndpointBusy => f.write_str("Endpoint has unreaped transfers queued"),
+            Error::InvalidStringDescriptor => f.write_str("Invalid string descriptor"),
+            Error::InvalidSysfsEntry => f.write_str("Invalid or missing sysfs attribute"),
+            Error::TimedOut => f.write_str("Transfer timed out"),
         }
     }
 }
 
+/// Finds the first device matching `vid`/`pid` and claims the interface
+/// owning `endpoint_address`. A thin convenience wrapper around
+/// [`DeviceFilter`] for the common single-device case.
 pub fn open_device_vid_pid_endpoint(vid: u16, pid: u16, endpoint_address: u8) -> Result<UsbDevice> {
-    let vid_str = format!("{vid:04x}");
-    let pid_str = format!("{pid:04x}");
-    for device_path in std::fs::read_dir("/sys/bus/usb/devices/")? {
-        let device_path = device_path?.path();
-        if let (Ok(dev_vid), Ok(dev_pid), Ok(devnum), Ok(busnum)) = (
-            std::fs::read(device_path.join("idVendor")),
-            std::fs::read(device_path.join("idProduct")),
-            std::fs::read(device_path.join("devnum")),
-            std::fs::read(device_path.join("busnum")),
-        ) {
-            if &dev_vid[..4] == vid_str.as_bytes() && &dev_pid[..4] == pid_str.as_bytes() {
-                let devnum: usize = String::from_utf8(devnum).unwrap().trim().parse().unwrap();
-                let busnum: usize = String::from_utf8(busnum).unwrap().trim().parse().unwrap();
-                let usb_file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(format!("/dev/bus/usb/{busnum:03}/{devnum:03}"))?;
-                let usb_device = UsbDevice::new(usb_file)?;
-                usb_device.claim_endpoint(endpoint_address)?;
-                return Ok(usb_device);
-            }
-        }
-    }
-    Err(Error::NotFound)
+    let device_info = DeviceFilter::new()
+        .vid_pid(vid, pid)
+        .find()?
+        .into_iter()
+        .next()
+        .ok_or(Error::NotFound)?;
+    let usb_device = device_info.open()?;
+    usb_device.claim_endpoint(endpoint_address)?;
+    Ok(usb_device)
 }
@@ -0,0 +1,282 @@
+// Copyright (C) 2023, Alex Badics
+// This file is part of tiny-linux-usb
+// Licensed under the MIT license. See LICENSE file in the project root for details.
+
+//! USB Test & Measurement Class (USBTMC, USBTMC-USB488) support, for talking
+//! to oscilloscopes, DMMs and other lab instruments over bulk transfers.
+
+use std::{cell::Cell, time::Duration};
+
+use crate::{request_type, Direction, Recipient, RequestType, UsbDevice};
+
+const INTERFACE_CLASS: u8 = 0xFE;
+const INTERFACE_SUBCLASS: u8 = 3;
+const USB488_PROTOCOL: u8 = 1;
+
+const DEV_DEP_MSG_OUT: u8 = 1;
+const REQUEST_DEV_DEP_MSG_IN: u8 = 2;
+const DEV_DEP_MSG_IN: u8 = 2;
+
+const EOM: u8 = 1 << 0;
+
+const REQUEST_INITIATE_CLEAR: u8 = 5;
+const REQUEST_CHECK_CLEAR_STATUS: u8 = 6;
+const REQUEST_GET_CAPABILITIES: u8 = 7;
+const REQUEST_INDICATOR_PULSE: u8 = 64;
+
+const STATUS_SUCCESS: u8 = 0x01;
+const STATUS_PENDING: u8 = 0x02;
+
+/// Largest chunk requested per `REQUEST_DEV_DEP_MSG_IN`; larger responses
+/// come back over several bulk-IN packets, reassembled until EOM.
+const MAX_TRANSFER_SIZE: u32 = 4096;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Device(crate::Error),
+    /// The device doesn't expose a USBTMC interface, or it has no paired
+    /// bulk IN/OUT endpoints.
+    NoUsbtmcInterface,
+    /// A control request came back with a status other than
+    /// `STATUS_SUCCESS`. [`UsbtmcDevice::check_clear_status`] turns
+    /// `STATUS_PENDING` into `Ok(true)`; the other requests report it here.
+    RequestFailed(u8),
+    /// A bulk-IN response was shorter than the 12-byte USBTMC header, or
+    /// carried an unexpected `MsgID`.
+    InvalidResponse,
+}
+
+impl From<crate::Error> for Error {
+    fn from(value: crate::Error) -> Self {
+        Self::Device(value)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Device(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Device(e) => std::fmt::Display::fmt(&e, f),
+            Error::NoUsbtmcInterface => f.write_str("No USBTMC interface found"),
+            Error::RequestFailed(status) => write!(f, "USBTMC request failed, status {status:#x}"),
+            Error::InvalidResponse => f.write_str("Invalid USBTMC bulk-IN response"),
+        }
+    }
+}
+
+/// A device's capabilities, as reported by `GET_CAPABILITIES`.
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    pub bcd_usbtmc: u16,
+    pub supports_indicator_pulse: bool,
+    pub supports_term_char: bool,
+    /// USB488 only: the device is talk-only (can't be addressed to listen).
+    pub talk_only: bool,
+    /// USB488 only: the device is listen-only (can't be addressed to talk).
+    pub listen_only: bool,
+}
+
+/// A claimed USBTMC (optionally USB488) interface on a [`UsbDevice`].
+pub struct UsbtmcDevice {
+    device: UsbDevice,
+    interface_number: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    is_usb488: bool,
+    next_tag: Cell<u8>,
+}
+
+impl UsbtmcDevice {
+    /// Scans `device`'s descriptors for a USBTMC interface
+    /// (`bInterfaceClass == 0xFE`, `bInterfaceSubClass == 3`) with paired
+    /// bulk IN/OUT endpoints, and claims it.
+    pub fn new(device: UsbDevice) -> Result<Self> {
+        let found = {
+            let descriptors = device.descriptors()?;
+            descriptors
+                .configurations
+                .iter()
+                .flat_map(|c| &c.interfaces)
+                .find_map(|interface| {
+                    if interface.desc.bInterfaceClass != INTERFACE_CLASS
+                        || interface.desc.bInterfaceSubClass != INTERFACE_SUBCLASS
+                    {
+                        return None;
+                    }
+                    let bulk_in = interface
+                        .endpoints
+                        .iter()
+                        .find(|e| e.bEndpointAddress & 0x80 != 0 && e.bmAttributes & 0x3 == 2)?
+                        .bEndpointAddress;
+                    let bulk_out = interface
+                        .endpoints
+                        .iter()
+                        .find(|e| e.bEndpointAddress & 0x80 == 0 && e.bmAttributes & 0x3 == 2)?
+                        .bEndpointAddress;
+                    Some((
+                        interface.desc.bInterfaceNumber,
+                        bulk_in,
+                        bulk_out,
+                        interface.desc.bInterfaceProtocol == USB488_PROTOCOL,
+                    ))
+                })
+        };
+        let (interface_number, bulk_in, bulk_out, is_usb488) =
+            found.ok_or(Error::NoUsbtmcInterface)?;
+        device.claim_interface(interface_number)?;
+        Ok(Self {
+            device,
+            interface_number,
+            bulk_in,
+            bulk_out,
+            is_usb488,
+            next_tag: Cell::new(1),
+        })
+    }
+
+    pub fn is_usb488(&self) -> bool {
+        self.is_usb488
+    }
+
+    /// Sends `command` as a single `DEV_DEP_MSG_OUT` message.
+    pub fn write_scpi(&self, command: &str, timeout: Duration) -> Result<()> {
+        let payload = command.as_bytes();
+        let mut message = bulk_header(
+            DEV_DEP_MSG_OUT,
+            self.next_tag(),
+            payload.len() as u32,
+            EOM,
+            0,
+        );
+        message.extend_from_slice(payload);
+        pad_to_4_bytes(&mut message);
+        self.device.write_bulk(self.bulk_out, &message, timeout)?;
+        Ok(())
+    }
+
+    /// Requests a response via `REQUEST_DEV_DEP_MSG_IN`, reassembling
+    /// further bulk-IN packets until the device signals EOM.
+    pub fn read_scpi(&self, timeout: Duration) -> Result<String> {
+        let mut result = Vec::new();
+        loop {
+            let request = bulk_header(
+                REQUEST_DEV_DEP_MSG_IN,
+                self.next_tag(),
+                MAX_TRANSFER_SIZE,
+                0,
+                0,
+            );
+            self.device.write_bulk(self.bulk_out, &request, timeout)?;
+
+            let mut buf = vec![0u8; 12 + MAX_TRANSFER_SIZE as usize];
+            let n = self.device.read_bulk(self.bulk_in, &mut buf, timeout)?;
+            if n < 12 || buf[0] != DEV_DEP_MSG_IN {
+                return Err(Error::InvalidResponse);
+            }
+            let transfer_size = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+            let attributes = buf[8];
+            let payload_len = transfer_size.min(n - 12);
+            result.extend_from_slice(&buf[12..12 + payload_len]);
+            if attributes & EOM != 0 {
+                break;
+            }
+        }
+        Ok(String::from_utf8_lossy(&result).into_owned())
+    }
+
+    /// `GET_CAPABILITIES` (bRequest 7).
+    pub fn get_capabilities(&self, timeout: Duration) -> Result<Capabilities> {
+        let mut buf = [0u8; 0x18];
+        self.control_in(REQUEST_GET_CAPABILITIES, 0, &mut buf, timeout)?;
+        Ok(Capabilities {
+            bcd_usbtmc: u16::from_le_bytes([buf[2], buf[3]]),
+            supports_indicator_pulse: buf[4] & 0x04 != 0,
+            supports_term_char: buf[5] & 0x01 != 0,
+            listen_only: buf[15] & 0x01 != 0,
+            talk_only: buf[15] & 0x02 != 0,
+        })
+    }
+
+    /// Starts the `INITIATE_CLEAR`/`CHECK_CLEAR_STATUS` clear sequence
+    /// (bRequest 5), aborting any in-progress bulk transfer.
+    pub fn initiate_clear(&self, timeout: Duration) -> Result<()> {
+        let mut buf = [0u8; 1];
+        self.control_in(REQUEST_INITIATE_CLEAR, 0, &mut buf, timeout)?;
+        Ok(())
+    }
+
+    /// Polls the clear sequence (bRequest 6), returning `true` while the
+    /// device still reports `STATUS_PENDING`.
+    pub fn check_clear_status(&self, timeout: Duration) -> Result<bool> {
+        let mut buf = [0u8; 2];
+        match self.control_in(REQUEST_CHECK_CLEAR_STATUS, 0, &mut buf, timeout) {
+            Ok(()) => Ok(false),
+            Err(Error::RequestFailed(STATUS_PENDING)) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// `INDICATOR_PULSE` (bRequest 64): blinks the instrument's status
+    /// indicator, if `Capabilities::supports_indicator_pulse`.
+    pub fn indicator_pulse(&self, timeout: Duration) -> Result<()> {
+        let mut buf = [0u8; 1];
+        self.control_in(REQUEST_INDICATOR_PULSE, 0, &mut buf, timeout)?;
+        Ok(())
+    }
+
+    /// Issues a USBTMC class control IN request, validating the leading
+    /// status byte.
+    fn control_in(&self, request: u8, value: u16, buf: &mut [u8], timeout: Duration) -> Result<()> {
+        self.device.read_control(
+            request_type(Direction::In, RequestType::Class, Recipient::Interface),
+            request,
+            value,
+            self.interface_number as u16,
+            buf,
+            timeout,
+        )?;
+        match buf[0] {
+            STATUS_SUCCESS => Ok(()),
+            status => Err(Error::RequestFailed(status)),
+        }
+    }
+
+    fn next_tag(&self) -> u8 {
+        let tag = self.next_tag.get();
+        self.next_tag.set(if tag == 255 { 1 } else { tag + 1 });
+        tag
+    }
+}
+
+/// Builds the 12-byte USBTMC bulk transport header (`bLength`-less, as it
+/// isn't a standard USB descriptor): `MsgID`, `bTag`/`bTagInverse`, a
+/// reserved byte, `TransferSize`, `TransferAttributes`, `TermChar`, then two
+/// reserved bytes.
+fn bulk_header(msg_id: u8, tag: u8, transfer_size: u32, attributes: u8, term_char: u8) -> Vec<u8> {
+    let mut header = Vec::with_capacity(12);
+    header.push(msg_id);
+    header.push(tag);
+    header.push(!tag);
+    header.push(0); // Reserved
+    header.extend_from_slice(&transfer_size.to_le_bytes());
+    header.push(attributes);
+    header.push(term_char);
+    header.extend_from_slice(&[0, 0]); // Reserved
+    header
+}
+
+fn pad_to_4_bytes(buf: &mut Vec<u8>) {
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}